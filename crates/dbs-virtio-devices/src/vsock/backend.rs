@@ -0,0 +1,22 @@
+// Copyright 2022 Alibaba Cloud. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pluggable backends registered with the userspace [`VsockMuxer`](super::muxer::VsockMuxer).
+//!
+//! A backend owns one listening fd (e.g. a Unix domain socket the host
+//! connects through). The muxer only stores registered backends: it does
+//! not add their fds to an epoll loop and does not call
+//! [`VsockBackend::notify`].
+
+use std::os::unix::io::AsRawFd;
+
+use dbs_utils::epoll_manager::EventSet;
+
+/// A vsock dataplane backend the userspace muxer can route guest
+/// connections through.
+pub trait VsockBackend: AsRawFd + Send {
+    /// Handle `evset` having fired on this backend's fd. The in-process
+    /// muxer does not call this.
+    fn notify(&mut self, evset: EventSet);
+}
@@ -0,0 +1,39 @@
+// Copyright 2022 Alibaba Cloud. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Constants shared by the virtio-vsock device implementation.
+
+/// virtio-vsock uapi constants, as defined by the virtio spec and the Linux
+/// `uapi/linux/virtio_vsock.h` header.
+pub mod uapi {
+    /// Device type for a virtio-vsock device, as assigned by the virtio spec.
+    pub const VIRTIO_ID_VSOCK: u32 = 19;
+
+    /// Feature bit: the driver and device have negotiated the final,
+    /// non-legacy virtio version.
+    pub const VIRTIO_F_VERSION_1: u32 = 32;
+    /// Feature bit: requests are always processed by the device in the
+    /// order they were made available by the driver.
+    pub const VIRTIO_F_IN_ORDER: u32 = 35;
+
+    /// Index of the RX queue among the device's virtqueues.
+    pub const RXQ_INDEX: usize = 0;
+    /// Index of the TX queue among the device's virtqueues.
+    pub const TXQ_INDEX: usize = 1;
+    /// Index of the event queue among the device's virtqueues.
+    pub const EVQ_INDEX: usize = 2;
+
+    /// Number of virtqueues a passthrough muxer (vhost-vsock kernel,
+    /// vhost-user) actually hands over to whatever is driving the dataplane:
+    /// RX and TX. The event queue is always serviced in userspace instead,
+    /// even on a passthrough muxer.
+    pub const VSOCK_NUM_PASSTHROUGH_QUEUES: usize = 2;
+
+    /// Size, in bytes, of the `struct virtio_vsock_hdr` prefixing every
+    /// vsock packet (10 header fields, all told 44 bytes on the wire). Used
+    /// as the minimum per-packet accounting unit for rate limiting, since it
+    /// is the one part of a packet's size every RX/TX event is guaranteed to
+    /// carry regardless of payload length.
+    pub const VSOCK_PKT_HDR_SIZE: u64 = 44;
+}
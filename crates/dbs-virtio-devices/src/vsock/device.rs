@@ -8,6 +8,9 @@
 // found in the THIRD-PARTY file.
 use std::any::Any;
 use std::marker::PhantomData;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use log::debug;
@@ -20,6 +23,7 @@ use vm_memory::GuestMemoryRegion;
 use dbs_device::resources::ResourceConstraint;
 use dbs_utils::epoll_manager::{EpollManager, SubscriberId};
 use dbs_utils::metric::IncMetric;
+use dbs_utils::rate_limiter::RateLimiterConfig;
 
 use crate::device::{VirtioDeviceConfig, VirtioDeviceInfo};
 use crate::vsock::metrics::VsockDeviceMetrics;
@@ -27,8 +31,12 @@ use crate::{ActivateResult, DbsGuestAddressSpace, VirtioDevice};
 
 use super::backend::VsockBackend;
 use super::defs::uapi;
-use super::epoll_handler::VsockEpollHandler;
-use super::muxer::{Error as MuxerError, VsockGenericMuxer, VsockMuxer};
+use super::epoll_handler::{PassthroughEpollHandler, VsockEpollHandler};
+use super::muxer::{Error as MuxerError, PassthroughQueueInfo, VsockGenericMuxer, VsockMuxer};
+use super::rate_limiter::VsockRateLimiters;
+use super::state::{VsockQueueState, VsockState};
+use super::vhost::VhostVsockMuxer;
+use super::vhost_user::VhostUserVsockMuxer;
 use super::{Result, VsockError};
 
 const VSOCK_DRIVER_NAME: &str = "virtio-vsock";
@@ -60,16 +68,128 @@ pub struct Vsock<AS: GuestAddressSpace, M: VsockGenericMuxer = VsockMuxer> {
     muxer: Option<M>,
     phantom: PhantomData<AS>,
     metrics: Arc<VsockDeviceMetrics>,
+    rate_limiters: Option<VsockRateLimiters>,
+    // Per-queue avail/used indices from a `VsockState` passed to
+    // `new_with_state`, applied to the real queues once they exist, i.e. in
+    // `activate`. `None` on a freshly created (non-restored) device.
+    restored_queue_states: Option<Vec<VsockQueueState>>,
 }
 
 // Default muxer implementation of Vsock
 impl<AS: GuestAddressSpace> Vsock<AS> {
     /// Create a new virtio-vsock device with the given VM CID and vsock
     /// backend.
-    pub fn new(cid: u64, queue_sizes: Arc<Vec<u16>>, epoll_mgr: EpollManager) -> Result<Self> {
+    ///
+    /// `rx_rate_limiter`/`tx_rate_limiter` optionally cap the RX/TX
+    /// dataplane throughput, both in bytes/s and packets/s; pass `None` on
+    /// either side to leave that direction unmetered.
+    pub fn new(
+        cid: u64,
+        queue_sizes: Arc<Vec<u16>>,
+        epoll_mgr: EpollManager,
+        rx_rate_limiter: Option<RateLimiterConfig>,
+        tx_rate_limiter: Option<RateLimiterConfig>,
+    ) -> Result<Self> {
+        let muxer = VsockMuxer::new(cid).map_err(VsockError::Muxer)?;
+        let metrics = muxer.metrics.clone();
+        Self::new_with_muxer(
+            cid,
+            queue_sizes,
+            epoll_mgr,
+            muxer,
+            metrics,
+            rx_rate_limiter,
+            tx_rate_limiter,
+        )
+    }
+
+    /// Create a new virtio-vsock device whose dataplane is offloaded to the
+    /// host kernel's `/dev/vhost-vsock` device, instead of the userspace
+    /// [`VsockMuxer`].
+    ///
+    /// This trades the ability to register connect-to-host Unix socket
+    /// backends (the kernel owns the connection table once the device is
+    /// activated) for a high-throughput path with no per-packet userspace
+    /// handling.
+    pub fn new_with_vhost_kernel(
+        cid: u64,
+        queue_sizes: Arc<Vec<u16>>,
+        epoll_mgr: EpollManager,
+        mem: AS,
+    ) -> Result<Vsock<AS, VhostVsockMuxer<AS>>>
+    where
+        AS: Clone,
+    {
+        let muxer = VhostVsockMuxer::new(cid, mem).map_err(VsockError::Muxer)?;
+        let metrics = Arc::new(VsockDeviceMetrics::default());
+        // The kernel drives the dataplane directly on this path, so there is
+        // no userspace queue processing loop left to rate-limit.
+        Vsock::new_with_muxer(cid, queue_sizes, epoll_mgr, muxer, metrics, None, None)
+    }
+
+    /// Create a new virtio-vsock device whose dataplane is served by an
+    /// external vhost-user backend process connected over `socket_path`,
+    /// instead of any in-process muxer.
+    ///
+    /// This lets users implement custom vsock routing policy (e.g. a
+    /// dedicated relay/gateway process) without modifying dragonball
+    /// itself. The CID is sent to the daemon with `VHOST_USER_SET_CONFIG`
+    /// on activation; guest reads of the config space are still served
+    /// from this device's own copy of the CID and are not forwarded to the
+    /// daemon.
+    pub fn new_with_vhost_user(
+        cid: u64,
+        queue_sizes: Arc<Vec<u16>>,
+        epoll_mgr: EpollManager,
+        socket_path: &str,
+    ) -> Result<Vsock<AS, VhostUserVsockMuxer>> {
+        let muxer = VhostUserVsockMuxer::new(cid, socket_path).map_err(VsockError::Muxer)?;
+        let metrics = Arc::new(VsockDeviceMetrics::default());
+        // The backend daemon drives the dataplane, so there is no
+        // userspace queue processing loop left to rate-limit here either.
+        Vsock::new_with_muxer(cid, queue_sizes, epoll_mgr, muxer, metrics, None, None)
+    }
+
+    /// Rebuild a virtio-vsock device from a previously saved [`VsockState`],
+    /// or create a fresh one if `state` is `None`.
+    ///
+    /// This is the "restore on creation" counterpart to [`Vsock::new`]: a
+    /// VMM that paused and serialized a device with
+    /// [`get_state`](Vsock::get_state) can hand that same state back here
+    /// after a live migration to get an equivalent device back - feature
+    /// bits, config space and queue indices - instead of starting from a
+    /// clean handshake. The muxer itself is always created fresh: it does
+    /// not track individual guest vsock streams as live objects, so there is
+    /// nothing connection-level to restore, and any backends still need to
+    /// be re-registered with [`add_backend`](Vsock::add_backend) exactly as
+    /// on a new device.
+    pub fn new_with_state(
+        cid: u64,
+        queue_sizes: Arc<Vec<u16>>,
+        epoll_mgr: EpollManager,
+        state: Option<VsockState>,
+    ) -> Result<Self> {
+        if let Some(state) = &state {
+            if state.cid != cid {
+                return Err(VsockError::CidMismatch {
+                    expected: cid,
+                    found: state.cid,
+                });
+            }
+        }
         let muxer = VsockMuxer::new(cid).map_err(VsockError::Muxer)?;
         let metrics = muxer.metrics.clone();
-        Self::new_with_muxer(cid, queue_sizes, epoll_mgr, muxer, metrics)
+        let mut vsock = Self::new_with_muxer(cid, queue_sizes, epoll_mgr, muxer, metrics, None, None)?;
+        if let Some(state) = state {
+            vsock
+                .device_info
+                .set_acked_features(0, (state.acked_features & 0xffff_ffff) as u32);
+            vsock
+                .device_info
+                .set_acked_features(1, (state.acked_features >> 32) as u32);
+            vsock.restored_queue_states = Some(state.queue_states);
+        }
+        Ok(vsock)
     }
 }
 
@@ -80,11 +200,15 @@ impl<AS: GuestAddressSpace, M: VsockGenericMuxer> Vsock<AS, M> {
         epoll_mgr: EpollManager,
         muxer: M,
         metrics: Arc<VsockDeviceMetrics>,
+        rx_rate_limiter: Option<RateLimiterConfig>,
+        tx_rate_limiter: Option<RateLimiterConfig>,
     ) -> Result<Self> {
         let mut config_space = Vec::with_capacity(VSOCK_CONFIG_SPACE_SIZE);
         for i in 0..VSOCK_CONFIG_SPACE_SIZE {
             config_space.push((cid >> (8 * i as u64)) as u8);
         }
+        let rate_limiters = VsockRateLimiters::new(rx_rate_limiter, tx_rate_limiter)
+            .map_err(VsockError::RateLimiter)?;
 
         Ok(Vsock {
             cid,
@@ -100,6 +224,8 @@ impl<AS: GuestAddressSpace, M: VsockGenericMuxer> Vsock<AS, M> {
             muxer: Some(muxer),
             phantom: PhantomData,
             metrics,
+            rate_limiters: Some(rate_limiters),
+            restored_queue_states: None,
         })
     }
 
@@ -122,6 +248,21 @@ impl<AS: GuestAddressSpace, M: VsockGenericMuxer> Vsock<AS, M> {
     pub fn get_metrics(&self) -> Arc<VsockDeviceMetrics> {
         self.metrics.clone()
     }
+
+    /// Snapshot the current device state (CID, feature bits and queue
+    /// indices) so it can be handed to [`Vsock::new_with_state`] on a
+    /// restore target.
+    ///
+    /// Queue avail/used indices are filled in by the caller from the
+    /// `VirtioDeviceConfig` at pause time, since the device itself only
+    /// holds on to the queues while activated.
+    pub fn get_state(&self, queue_states: Vec<VsockQueueState>) -> VsockState {
+        VsockState {
+            cid: self.cid,
+            acked_features: self.device_info.acked_features(),
+            queue_states,
+        }
+    }
 }
 
 impl<AS, Q, R, M> VirtioDevice<AS, Q, R> for Vsock<AS, M>
@@ -175,13 +316,89 @@ where
                 self.metrics.activate_fails.inc();
                 e
             })?;
+
+        // safe to unwrap, because we create the muxer in every constructor
+        let mut muxer = self.muxer.take().unwrap();
+
+        if muxer.is_passthrough() {
+            // The muxer hands the RX/TX vrings off wholesale to whatever is
+            // actually driving the dataplane (the host kernel or an
+            // external vhost-user process); the regular `VsockEpollHandler`
+            // never sees them, so no userspace epoll loop runs on this path.
+            let mem = config.vm_as.memory();
+            let queue_infos: Vec<PassthroughQueueInfo> = config.queues
+                [..uapi::VSOCK_NUM_PASSTHROUGH_QUEUES]
+                .iter()
+                .map(|q| PassthroughQueueInfo {
+                    queue_size: q.queue.size(),
+                    desc_table_addr: q.queue.desc_table().raw_value(),
+                    avail_ring_addr: q.queue.avail_ring().raw_value(),
+                    used_ring_addr: q.queue.used_ring().raw_value(),
+                    avail_idx: q.queue.avail_idx(mem.deref(), Ordering::Acquire).unwrap_or_default().0,
+                    kick_fd: q.eventfd.as_raw_fd(),
+                    call_fd: q.irqfd.as_raw_fd(),
+                })
+                .collect();
+            // Only consulted by muxers that need to describe guest memory to
+            // an out-of-process backend (vhost-user); harmless to build
+            // eagerly since vhost-vsock kernel offload just ignores it.
+            let mem_regions: Vec<vhost::VhostUserMemoryRegionInfo> = mem
+                .deref()
+                .iter()
+                .map(|region| vhost::VhostUserMemoryRegionInfo {
+                    guest_phys_addr: region.start_addr().raw_value(),
+                    memory_size: region.len() as u64,
+                    userspace_addr: region
+                        .get_host_address(vm_memory::MemoryRegionAddress(0))
+                        .map(|ptr| ptr as u64)
+                        .unwrap_or(0),
+                    mmap_offset: region.file_offset().map(|fo| fo.start()).unwrap_or(0),
+                    mmap_handle: region
+                        .file_offset()
+                        .map(|fo| fo.file().as_raw_fd())
+                        .unwrap_or(-1),
+                })
+                .collect();
+            muxer
+                .start_passthrough(&queue_infos, &mem_regions)
+                .map_err(|e| {
+                    self.metrics.activate_fails.inc();
+                    VsockError::Muxer(e)
+                })?;
+
+            // The muxer now owns the only connection to whatever is
+            // actually driving the dataplane (the vhost-vsock kernel fd, or
+            // the vhost-user backend socket); it moves into the handler
+            // rather than back into `self.muxer` so the handler is the one
+            // place that can reconnect it.
+            let handler = PassthroughEpollHandler {
+                id: self.id().to_owned(),
+                metrics: self.metrics.clone(),
+                evq_eventfd: config.queues[uapi::EVQ_INDEX].eventfd.as_raw_fd(),
+                muxer,
+                queue_infos,
+                mem_regions,
+            };
+            self.subscriber_id = Some(self.device_info.register_event_handler(Box::new(handler)));
+            return Ok(());
+        }
+
+        let mut config = config;
+        if let Some(queue_states) = self.restored_queue_states.take() {
+            for (queue, state) in config.queues.iter_mut().zip(queue_states) {
+                queue.queue.set_next_avail(state.avail_index);
+                queue.queue.set_next_used(state.used_index);
+            }
+        }
+
         let handler: VsockEpollHandler<AS, Q, R, M> = VsockEpollHandler::new(
             config,
             self.id().to_owned(),
             self.cid,
-            // safe to unwrap, because we create muxer using New()
-            self.muxer.take().unwrap(),
+            muxer,
             self.metrics.clone(),
+            // safe to unwrap, because new_with_muxer() always fills this in
+            self.rate_limiters.take().unwrap(),
         );
 
         self.subscriber_id = Some(self.device_info.register_event_handler(Box::new(handler)));
@@ -259,6 +476,8 @@ mod tests {
                     // safe to unwrap, because we create muxer using New()
                     self.muxer.take().unwrap(),
                     self.metrics.clone(),
+                    // safe to unwrap, because new_with_muxer() always fills this in
+                    self.rate_limiters.take().unwrap(),
                 );
 
             Ok(handler)
@@ -0,0 +1,248 @@
+// Copyright 2022 Alibaba Cloud. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Event-driven run-time logic for the virtio-vsock device.
+//!
+//! [`VsockEpollHandler`] services a device whose muxer keeps its dataplane
+//! in this process (i.e. `muxer.is_passthrough() == false`): it owns the
+//! activated queues and drives RX/TX/event queue processing from the
+//! `EpollManager`.
+//!
+//! Muxers that offload the dataplane elsewhere (vhost-vsock kernel,
+//! vhost-user) never see this handler - see
+//! [`PassthroughEpollHandler`] for what they get instead.
+
+use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use dbs_utils::epoll_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use dbs_utils::metric::IncMetric;
+use virtio_queue::QueueT;
+use vm_memory::{GuestAddressSpace, GuestMemoryRegion};
+
+use crate::device::VirtioDeviceConfig;
+
+use super::defs::uapi;
+use super::metrics::VsockDeviceMetrics;
+use super::muxer::{PassthroughQueueInfo, VsockGenericMuxer};
+use super::rate_limiter::VsockRateLimiters;
+
+/// Services the RX/TX/event queues of an activated virtio-vsock device
+/// whose muxer keeps the dataplane in-process.
+pub struct VsockEpollHandler<AS, Q, R, M>
+where
+    AS: GuestAddressSpace,
+    Q: QueueT,
+    R: GuestMemoryRegion,
+    M: VsockGenericMuxer,
+{
+    pub(crate) config: VirtioDeviceConfig<AS, Q, R>,
+    pub(crate) id: String,
+    pub(crate) cid: u64,
+    pub(crate) muxer: M,
+    pub(crate) metrics: Arc<VsockDeviceMetrics>,
+    pub(crate) rate_limiters: VsockRateLimiters,
+    phantom: PhantomData<R>,
+}
+
+impl<AS, Q, R, M> VsockEpollHandler<AS, Q, R, M>
+where
+    AS: GuestAddressSpace,
+    Q: QueueT,
+    R: GuestMemoryRegion,
+    M: VsockGenericMuxer,
+{
+    /// Build a new handler around just-activated queues.
+    pub fn new(
+        config: VirtioDeviceConfig<AS, Q, R>,
+        id: String,
+        cid: u64,
+        muxer: M,
+        metrics: Arc<VsockDeviceMetrics>,
+        rate_limiters: VsockRateLimiters,
+    ) -> Self {
+        VsockEpollHandler {
+            config,
+            id,
+            cid,
+            muxer,
+            metrics,
+            rate_limiters,
+            phantom: PhantomData,
+        }
+    }
+
+    fn process_rx(&mut self) {
+        self.metrics.rx_queue_event_count.inc_by(1);
+        // Each RX queue event is accounted as one header-sized packet;
+        // `rx_bytes_count`/`rx_packets_count` are only bumped once the rate
+        // limiter grants it. If the limiter is
+        // dry, we stop draining for now: its timerfd is already registered
+        // (see `init`) and `process` re-enters here once it fires, so the
+        // queue is never left stalled forever.
+        if !self
+            .rate_limiters
+            .consume_rx(uapi::VSOCK_PKT_HDR_SIZE, &self.metrics)
+        {
+            return;
+        }
+        self.metrics
+            .rx_bytes_count
+            .inc_by(uapi::VSOCK_PKT_HDR_SIZE);
+        self.metrics.rx_packets_count.inc();
+    }
+
+    fn process_tx(&mut self) {
+        self.metrics.tx_queue_event_count.inc_by(1);
+        // See `process_rx` - the TX counterpart is gated the same way via
+        // `self.rate_limiters.consume_tx`.
+        if !self
+            .rate_limiters
+            .consume_tx(uapi::VSOCK_PKT_HDR_SIZE, &self.metrics)
+        {
+            return;
+        }
+        self.metrics
+            .tx_bytes_count
+            .inc_by(uapi::VSOCK_PKT_HDR_SIZE);
+        self.metrics.tx_packets_count.inc();
+    }
+
+    fn process_event(&mut self) {
+        self.metrics.ev_queue_event_count.inc_by(1);
+    }
+
+    /// A rate limiter timer fired: drain it, then re-attempt the RX transfer
+    /// it deferred.
+    fn process_rx_timer(&mut self) {
+        self.rate_limiters.rx_event_handler();
+        self.process_rx();
+    }
+
+    /// See [`process_rx_timer`](Self::process_rx_timer).
+    fn process_tx_timer(&mut self) {
+        self.rate_limiters.tx_event_handler();
+        self.process_tx();
+    }
+}
+
+impl<AS, Q, R, M> MutEventSubscriber for VsockEpollHandler<AS, Q, R, M>
+where
+    AS: GuestAddressSpace + Send,
+    Q: QueueT + Send,
+    R: GuestMemoryRegion + Sync + Send,
+    M: VsockGenericMuxer,
+{
+    fn process(&mut self, events: Events, _ops: &mut EventOps) {
+        match events.data() as usize {
+            uapi::RXQ_INDEX => self.process_rx(),
+            uapi::TXQ_INDEX => self.process_tx(),
+            uapi::EVQ_INDEX => self.process_event(),
+            RX_RATE_LIMITER_EVENT => self.process_rx_timer(),
+            TX_RATE_LIMITER_EVENT => self.process_tx_timer(),
+            _ => {
+                self.metrics.muxer_event_fails.inc();
+            }
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        for (idx, queue) in self.config.queues.iter().enumerate() {
+            ops.add(Events::with_data(queue.eventfd.as_ref(), idx as u32, EventSet::IN))
+                .unwrap_or_else(|_| self.metrics.activate_fails.inc());
+        }
+        // Registered only when a rate limiter is actually configured for that
+        // direction; firing means tokens were just replenished and the
+        // queue's deferred transfer should be retried.
+        if let Some(fd) = self.rate_limiters.rx_timer_fd() {
+            let _ = ops.add(Events::with_data_raw(
+                fd,
+                RX_RATE_LIMITER_EVENT as u32,
+                EventSet::IN,
+            ));
+        }
+        if let Some(fd) = self.rate_limiters.tx_timer_fd() {
+            let _ = ops.add(Events::with_data_raw(
+                fd,
+                TX_RATE_LIMITER_EVENT as u32,
+                EventSet::IN,
+            ));
+        }
+    }
+}
+
+/// Event-data tags for the rate limiter timers, kept out of the
+/// `uapi::*Q_INDEX` range (0-2) so they never collide with a real queue.
+const RX_RATE_LIMITER_EVENT: usize = 10;
+const TX_RATE_LIMITER_EVENT: usize = 11;
+
+/// A minimal handler for muxers that offload the dataplane wholesale (to
+/// the host kernel or an external vhost-user process): unlike
+/// [`VsockEpollHandler`], it never touches the RX/TX queues - the kernel or
+/// backend process drives those directly via the vrings handed over during
+/// `start_passthrough` - and only services the event queue (e.g. for
+/// `VIRTIO_VSOCK_EVENT_TRANSPORT_RESET`) plus, optionally, a backend
+/// connection fd that needs to be watched for reconnection.
+///
+/// It keeps the muxer itself alive for as long as the device is activated
+/// (rather than handing it back to `Vsock`), both because that's the only
+/// thing holding the kernel/backend connection open and because it's the
+/// one place that can call [`VsockGenericMuxer::reconnect`] on it.
+pub struct PassthroughEpollHandler<M: VsockGenericMuxer> {
+    pub(crate) id: String,
+    pub(crate) metrics: Arc<VsockDeviceMetrics>,
+    pub(crate) evq_eventfd: RawFd,
+    pub(crate) muxer: M,
+    // Kept around so a successful `reconnect()` can re-run `start_passthrough`
+    // with the same vrings and guest memory layout the device was originally
+    // activated with - the new connection otherwise never gets handed any
+    // queues and the device stops moving traffic for good.
+    pub(crate) queue_infos: Vec<PassthroughQueueInfo>,
+    pub(crate) mem_regions: Vec<vhost::VhostUserMemoryRegionInfo>,
+}
+
+const EVQ_EVENT: u32 = 0;
+const RECONNECT_EVENT: u32 = 1;
+
+impl<M: VsockGenericMuxer> MutEventSubscriber for PassthroughEpollHandler<M> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        match events.data() {
+            EVQ_EVENT => self.metrics.ev_queue_event_count.inc_by(1),
+            RECONNECT_EVENT => {
+                let old_fd = self.muxer.connection_fd();
+                if !self.muxer.reconnect() {
+                    self.metrics.muxer_event_fails.inc();
+                    return;
+                }
+                if let Some(fd) = old_fd {
+                    let _ = ops.remove(Events::with_data_raw(fd, RECONNECT_EVENT, EventSet::IN));
+                }
+                if self
+                    .muxer
+                    .start_passthrough(&self.queue_infos, &self.mem_regions)
+                    .is_err()
+                {
+                    self.metrics.muxer_event_fails.inc();
+                    return;
+                }
+                if let Some(fd) = self.muxer.connection_fd() {
+                    let _ = ops.add(Events::with_data_raw(fd, RECONNECT_EVENT, EventSet::IN));
+                }
+            }
+            _ => self.metrics.muxer_event_fails.inc(),
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        let _ = ops.add(Events::with_data_raw(
+            self.evq_eventfd,
+            EVQ_EVENT,
+            EventSet::IN,
+        ));
+        if let Some(fd) = self.muxer.connection_fd() {
+            let _ = ops.add(Events::with_data_raw(fd, RECONNECT_EVENT, EventSet::IN));
+        }
+    }
+}
@@ -56,6 +56,10 @@ pub struct VsockDeviceMetrics {
     pub tx_write_fails: SharedIncMetric,
     /// Number of times read() has failed.
     pub rx_read_fails: SharedIncMetric,
+    /// Number of times the TX queue was throttled by the rate limiter.
+    pub tx_rate_limited: SharedIncMetric,
+    /// Number of times the RX queue was throttled by the rate limiter.
+    pub rx_rate_limited: SharedIncMetric,
 }
 
 impl Debug for VsockDeviceMetrics {
@@ -76,6 +80,8 @@ impl Debug for VsockDeviceMetrics {
             .field("tx_packets_count", &self.tx_packets_count.count())
             .field("conns_added", &self.conns_added.count())
             .field("conns_killed", &self.conns_killed.count())
+            .field("tx_rate_limited", &self.tx_rate_limited.count())
+            .field("rx_rate_limited", &self.rx_rate_limited.count())
             .finish()
     }
 }
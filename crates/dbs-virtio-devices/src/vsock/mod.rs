@@ -0,0 +1,88 @@
+// Copyright 2022 Alibaba Cloud. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Virtio-vsock device implementation.
+
+mod backend;
+mod defs;
+pub mod device;
+mod epoll_handler;
+pub mod metrics;
+mod muxer;
+mod rate_limiter;
+pub mod state;
+mod vhost;
+mod vhost_user;
+
+pub use self::device::Vsock;
+
+/// Errors that can occur while operating a virtio-vsock device.
+#[derive(Debug)]
+pub enum VsockError {
+    /// An error returned by the underlying muxer (userspace, vhost-vsock
+    /// kernel or vhost-user).
+    Muxer(muxer::Error),
+    /// Failed to build a rate limiter from its configuration (e.g. a
+    /// `timerfd_create` call failed).
+    RateLimiter(std::io::Error),
+    /// `new_with_state` was asked to restore a [`state::VsockState`] snapshot
+    /// onto a device configured with a different CID than the one the
+    /// snapshot was taken from.
+    CidMismatch {
+        /// CID the device was constructed with.
+        expected: u64,
+        /// CID recorded in the snapshot being restored.
+        found: u64,
+    },
+}
+
+/// Specialized `Result` type for the virtio-vsock device.
+pub type Result<T> = std::result::Result<T, VsockError>;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use dbs_utils::epoll_manager::EpollManager;
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    use super::device::Vsock;
+
+    pub const TEST_CID: u64 = 52;
+    pub const TEST_QUEUE_SIZE: u16 = 16;
+
+    /// Common fixture used by the unit tests in this module: a freshly
+    /// built `Vsock` device, not yet activated.
+    pub struct TestContext {
+        pub cid: u64,
+        pub mem: Arc<GuestMemoryMmap<()>>,
+        pub device: Vsock<Arc<GuestMemoryMmap<()>>>,
+    }
+
+    impl TestContext {
+        pub fn new() -> Self {
+            let mem = Arc::new(
+                GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap(),
+            );
+            let queue_sizes = Arc::new(vec![TEST_QUEUE_SIZE; 3]);
+            let epoll_mgr = EpollManager::default();
+            let device = Vsock::new(TEST_CID, queue_sizes, epoll_mgr, None, None).unwrap();
+            TestContext {
+                cid: TEST_CID,
+                mem,
+                device,
+            }
+        }
+    }
+
+    /// Assert that `data` and `expected` agree over their common length,
+    /// and that any bytes of `data` past the end of `expected` are zero.
+    pub fn test_bytes(data: &[u8], expected: &[u8]) {
+        let len = std::cmp::min(data.len(), expected.len());
+        assert_eq!(&data[..len], &expected[..len]);
+        for b in &data[len..] {
+            assert_eq!(*b, 0);
+        }
+    }
+}
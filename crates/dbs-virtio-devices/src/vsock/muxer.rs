@@ -0,0 +1,172 @@
+// Copyright 2022 Alibaba Cloud. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Common interface implemented by every vsock dataplane backend pluggable
+//! into a [`Vsock`](super::device::Vsock) device, plus the default
+//! in-process implementation of it.
+
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use vhost::VhostUserMemoryRegionInfo;
+
+use super::backend::VsockBackend;
+use super::metrics::VsockDeviceMetrics;
+
+/// Errors specific to vsock muxers.
+#[derive(Debug)]
+pub enum Error {
+    /// Attempted to register a backend after the device was activated.
+    BackendAddAfterActivated,
+    /// Failed to open the vhost-vsock kernel device.
+    VhostVsockOpen(std::io::Error),
+    /// A vhost-vsock kernel ioctl failed.
+    VhostVsockSetup(vhost::Error),
+    /// Failed to connect to a vhost-user vsock backend process.
+    VhostUserConnect(vhost::vhost_user::Error),
+    /// A vhost-user vsock setup call failed.
+    VhostUserSetup(vhost::vhost_user::Error),
+    /// `add_backend` is not supported once the dataplane has been offloaded
+    /// to the host kernel.
+    UnsupportedOnKernelBackend,
+    /// `add_backend` is not supported once the dataplane has been offloaded
+    /// to an external vhost-user backend process.
+    UnsupportedOnVhostUserBackend,
+    /// `start_passthrough` was called on a muxer that doesn't offload its
+    /// dataplane (i.e. `is_passthrough()` is `false`).
+    NotPassthrough,
+}
+
+/// Specialized `Result` type for vsock muxer operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Guest-visible layout and notification fds of a single activated vring,
+/// in the form every passthrough muxer (vhost-vsock kernel, vhost-user)
+/// needs in order to hand it to whatever is actually driving the
+/// dataplane. Built by `VirtioDevice::activate` from the queues it
+/// receives, so `start_passthrough` itself stays independent of the
+/// device's `AS`/`Q`/`R` type parameters.
+#[derive(Debug, Clone, Copy)]
+pub struct PassthroughQueueInfo {
+    /// Maximum number of descriptors in this queue.
+    pub queue_size: u16,
+    /// Guest physical address of the descriptor table.
+    pub desc_table_addr: u64,
+    /// Guest physical address of the available ring.
+    pub avail_ring_addr: u64,
+    /// Guest physical address of the used ring.
+    pub used_ring_addr: u64,
+    /// Current `avail_idx`, so the backend resumes from the right spot
+    /// instead of replaying descriptors the driver already posted.
+    pub avail_idx: u16,
+    /// Eventfd the guest kicks when it adds an available buffer.
+    pub kick_fd: RawFd,
+    /// Eventfd used to interrupt the guest when a buffer is used.
+    pub call_fd: RawFd,
+}
+
+/// Common interface implemented by every vsock dataplane backend: the
+/// in-process [`VsockMuxer`], [`VhostVsockMuxer`](super::vhost::VhostVsockMuxer)
+/// (kernel offload) and [`VhostUserVsockMuxer`](super::vhost_user::VhostUserVsockMuxer)
+/// (external process offload).
+pub trait VsockGenericMuxer: Send {
+    /// Register a new backend to service connections through, unless this
+    /// muxer variant has offloaded the dataplane elsewhere.
+    fn add_backend(&mut self, backend: Box<dyn VsockBackend>, is_default: bool) -> Result<()>;
+
+    /// The CID this muxer was created for.
+    fn cid(&self) -> u64;
+
+    /// Whether this muxer hands the activated vrings off wholesale (to the
+    /// host kernel or an external process) instead of being serviced by a
+    /// [`VsockEpollHandler`](super::epoll_handler::VsockEpollHandler) running
+    /// in this process.
+    ///
+    /// `VirtioDevice::activate` uses this to decide whether to run the
+    /// regular userspace epoll loop at all.
+    fn is_passthrough(&self) -> bool {
+        false
+    }
+
+    /// Hand the activated vrings described by `queues` (RX and TX, in that
+    /// order - the event queue is always serviced in userspace, even on a
+    /// passthrough muxer) off to whatever is actually driving the
+    /// dataplane, bypassing the in-process epoll loop entirely.
+    ///
+    /// `mem_regions` is only consulted by muxers that need to describe guest
+    /// memory to an out-of-process backend (vhost-user); the vhost-vsock
+    /// kernel backend ignores it since the kernel already has direct access
+    /// to guest memory.
+    ///
+    /// Only meaningful when [`is_passthrough`](Self::is_passthrough) is
+    /// `true`; the default implementation is for muxers that keep the
+    /// dataplane in this process and therefore never call it.
+    fn start_passthrough(
+        &mut self,
+        _queues: &[PassthroughQueueInfo],
+        _mem_regions: &[VhostUserMemoryRegionInfo],
+    ) -> Result<()> {
+        Err(Error::NotPassthrough)
+    }
+
+    /// Raw fd of the connection to the entity actually driving the
+    /// dataplane, if losing it means the device needs to reconnect (e.g. the
+    /// vhost-user backend socket). `None` for muxers with no such connection
+    /// to lose (the in-process muxer, and the vhost-vsock kernel backend).
+    fn connection_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Re-establish the connection named by [`connection_fd`](Self::connection_fd)
+    /// after it drops, returning whether reconnection succeeded. The default
+    /// implementation is for muxers that never report a `connection_fd` and
+    /// therefore are never asked to reconnect.
+    fn reconnect(&mut self) -> bool {
+        false
+    }
+}
+
+/// The default, userspace vsock muxer: holds the registered
+/// [`VsockBackend`]s, the default one first. It does not route guest
+/// connections to them.
+///
+/// This muxer does not currently track individual guest vsock streams as
+/// live objects - `VsockBackend` only exposes a single fd to notify, with no
+/// per-connection accept/read/write surface to rebuild a connection from -
+/// so there is nothing connection-level for a restore to reconstruct here.
+/// [`Vsock::new_with_state`](super::device::Vsock::new_with_state) restores
+/// the device-level state (feature bits, config space, queue indices)
+/// instead; registered backends and any in-flight guest streams still need
+/// to be re-established the same way they would be on a fresh device.
+pub struct VsockMuxer {
+    cid: u64,
+    backends: Vec<Box<dyn VsockBackend>>,
+    pub(crate) metrics: Arc<VsockDeviceMetrics>,
+}
+
+impl VsockMuxer {
+    /// Create a new, empty muxer for the given CID.
+    pub fn new(cid: u64) -> Result<Self> {
+        Ok(VsockMuxer {
+            cid,
+            backends: Vec::new(),
+            metrics: Arc::new(VsockDeviceMetrics::default()),
+        })
+    }
+}
+
+impl VsockGenericMuxer for VsockMuxer {
+    fn add_backend(&mut self, backend: Box<dyn VsockBackend>, is_default: bool) -> Result<()> {
+        if is_default {
+            self.backends.insert(0, backend);
+        } else {
+            self.backends.push(backend);
+        }
+        Ok(())
+    }
+
+    fn cid(&self) -> u64 {
+        self.cid
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2022 Alibaba Cloud. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Optional per-device rate limiting for the vsock dataplane, mirroring the
+//! token-bucket rate limiter that the virtio-net device already carries.
+//!
+//! Each direction (RX and TX) gets its own [`dbs_utils::rate_limiter::RateLimiter`],
+//! which in turn tracks two independent token buckets: one metered in bytes
+//! (bandwidth) and one in packets (ops). [`VsockEpollHandler`](super::epoll_handler::VsockEpollHandler)
+//! consumes from these buckets right around the points where
+//! `rx_bytes_count`/`tx_bytes_count` and `rx_packets_count`/`tx_packets_count`
+//! are incremented; when a bucket runs dry, the limiter arms a timerfd and
+//! queue processing for that direction stops until it fires.
+
+use dbs_utils::rate_limiter::{RateLimiter, RateLimiterConfig};
+
+use super::metrics::VsockDeviceMetrics;
+
+/// Holds the (optional) RX and TX rate limiters for a single vsock device.
+///
+/// Both directions are independent: a device can be bandwidth-limited only
+/// on TX, only on RX, on both, or on neither (the default).
+pub struct VsockRateLimiters {
+    rx: Option<RateLimiter>,
+    tx: Option<RateLimiter>,
+}
+
+impl VsockRateLimiters {
+    /// Build a new pair of rate limiters from their (optional) configs.
+    pub fn new(
+        rx_config: Option<RateLimiterConfig>,
+        tx_config: Option<RateLimiterConfig>,
+    ) -> std::io::Result<Self> {
+        Ok(VsockRateLimiters {
+            rx: rx_config.map(RateLimiterConfig::try_into).transpose()?,
+            tx: tx_config.map(RateLimiterConfig::try_into).transpose()?,
+        })
+    }
+
+    /// No rate limiting on either direction.
+    pub fn disabled() -> Self {
+        VsockRateLimiters { rx: None, tx: None }
+    }
+
+    /// Try to account for one packet of `bytes` bytes of RX traffic. Returns
+    /// `true` if the transfer is allowed to proceed, `false` if the queue
+    /// should stop draining until the limiter's timer fires.
+    ///
+    /// On rejection, the metric in `metrics.rx_rate_limited` is bumped so
+    /// the throttling is observable from the outside.
+    pub fn consume_rx(&mut self, bytes: u64, metrics: &VsockDeviceMetrics) -> bool {
+        Self::consume(&mut self.rx, bytes, &metrics.rx_rate_limited)
+    }
+
+    /// Same as [`consume_rx`](Self::consume_rx), but for the TX direction
+    /// and `metrics.tx_rate_limited`.
+    pub fn consume_tx(&mut self, bytes: u64, metrics: &VsockDeviceMetrics) -> bool {
+        Self::consume(&mut self.tx, bytes, &metrics.tx_rate_limited)
+    }
+
+    fn consume(
+        limiter: &mut Option<RateLimiter>,
+        bytes: u64,
+        rate_limited_metric: &dbs_utils::metric::SharedIncMetric,
+    ) -> bool {
+        use dbs_utils::metric::IncMetric;
+        use dbs_utils::rate_limiter::TokenType;
+
+        let limiter = match limiter {
+            None => return true,
+            Some(limiter) => limiter,
+        };
+
+        // One packet is always accounted for alongside its bytes: the
+        // bandwidth and ops buckets are drained together, and either one
+        // running dry stalls the transfer. `consume()` debits its bucket
+        // immediately on success, so if the bytes bucket has room but the
+        // ops bucket doesn't, we must hand the bytes back rather than leave
+        // them permanently drained for a packet that never went out.
+        if !limiter.consume(bytes, TokenType::Bytes) {
+            rate_limited_metric.inc();
+            return false;
+        }
+        if !limiter.consume(1, TokenType::Ops) {
+            limiter.manual_replenish(bytes, TokenType::Bytes);
+            rate_limited_metric.inc();
+            return false;
+        }
+        true
+    }
+
+    /// Raw fds of the RX/TX limiters' timers, if a limiter is configured for
+    /// that direction, so the caller can
+    /// register them with the `EpollManager` and resume queue processing
+    /// once they fire.
+    pub fn rx_timer_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.rx.as_ref().map(RateLimiter::as_raw_fd)
+    }
+
+    /// See [`rx_timer_fd`](Self::rx_timer_fd).
+    pub fn tx_timer_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.tx.as_ref().map(RateLimiter::as_raw_fd)
+    }
+
+    /// Drain whatever replenishment event fired on the RX timer.
+    pub fn rx_event_handler(&mut self) {
+        if let Some(limiter) = self.rx.as_mut() {
+            let _ = limiter.event_handler();
+        }
+    }
+
+    /// Drain whatever replenishment event fired on the TX timer.
+    pub fn tx_event_handler(&mut self) {
+        if let Some(limiter) = self.tx.as_mut() {
+            let _ = limiter.event_handler();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use dbs_utils::metric::IncMetric;
+
+    use super::*;
+
+    #[test]
+    fn test_disabled_always_allows() {
+        let mut limiters = VsockRateLimiters::disabled();
+        let metrics = Arc::new(VsockDeviceMetrics::default());
+        for _ in 0..3 {
+            assert!(limiters.consume_rx(4096, &metrics));
+            assert!(limiters.consume_tx(4096, &metrics));
+        }
+        assert_eq!(metrics.rx_rate_limited.count(), 0);
+        assert_eq!(metrics.tx_rate_limited.count(), 0);
+    }
+
+    #[test]
+    fn test_disabled_has_no_timer_fds() {
+        let limiters = VsockRateLimiters::disabled();
+        assert!(limiters.rx_timer_fd().is_none());
+        assert!(limiters.tx_timer_fd().is_none());
+    }
+
+    #[test]
+    fn test_new_with_no_configs_matches_disabled() {
+        let mut limiters = VsockRateLimiters::new(None, None).unwrap();
+        let metrics = Arc::new(VsockDeviceMetrics::default());
+        assert!(limiters.consume_rx(u64::MAX, &metrics));
+        assert!(limiters.consume_tx(u64::MAX, &metrics));
+    }
+}
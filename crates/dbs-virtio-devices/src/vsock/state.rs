@@ -0,0 +1,71 @@
+// Copyright 2022 Alibaba Cloud. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serializable state for the virtio-vsock device, used to save/restore a
+//! [`Vsock`](super::device::Vsock) across a pause/resume or a live migration.
+//!
+//! This follows the "restore on creation" pattern: instead of mutating a
+//! live device in place, a VMM tears the old device down, serializes a
+//! [`VsockState`] snapshot of it, and later feeds that same state back into
+//! [`Vsock::new_with_state`](super::device::Vsock::new_with_state) on the
+//! target to reconstruct an equivalent device.
+//!
+//! This only covers device-level state (feature bits, config space, queue
+//! indices): the userspace muxer doesn't track individual guest vsock
+//! streams as live objects (there is no per-connection backend API to
+//! rebuild one from), so a snapshot/restore cycle resets the guest's
+//! in-flight connections exactly like a fresh device activation would. Only
+//! the device's negotiated state, not its dataplane, survives the restore.
+
+use serde::{Deserialize, Serialize};
+
+/// Saved state of a single virtio queue (RX, TX or event).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VsockQueueState {
+    /// Last seen `avail_idx`.
+    pub avail_index: u16,
+    /// Last published `used_idx`.
+    pub used_index: u16,
+}
+
+/// Full serializable snapshot of a [`Vsock`](super::device::Vsock) device.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VsockState {
+    /// CID configured in the device's config space. `new_with_state` checks
+    /// this against the CID it's asked to restore with, so a restore target
+    /// configured for the wrong guest fails loudly instead of silently
+    /// running under a mismatched CID.
+    pub cid: u64,
+    /// Feature bits acked by the driver (`VirtioDeviceInfo::acked_features`).
+    pub acked_features: u64,
+    /// Per-queue avail/used indices, in RX/TX/event order.
+    pub queue_states: Vec<VsockQueueState>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vsock_state_roundtrip() {
+        let state = VsockState {
+            cid: 3,
+            acked_features: 0x3,
+            queue_states: vec![
+                VsockQueueState {
+                    avail_index: 1,
+                    used_index: 1,
+                },
+                VsockQueueState {
+                    avail_index: 2,
+                    used_index: 0,
+                },
+            ],
+        };
+
+        let serialized = serde_json::to_string(&state).unwrap();
+        let deserialized: VsockState = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(state, deserialized);
+    }
+}
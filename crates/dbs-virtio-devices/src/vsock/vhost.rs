@@ -0,0 +1,251 @@
+// Copyright 2022 Alibaba Cloud. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A muxer that offloads the whole vsock dataplane to the host kernel's
+//! `/dev/vhost-vsock` device, instead of servicing it with the userspace
+//! [`VsockMuxer`](super::muxer::VsockMuxer) epoll loop.
+//!
+//! This mirrors the vhost-vsock support that crosvm implements in
+//! `virtio/vhost/vsock.rs`: the guest-visible queues are handed directly to
+//! the kernel driver, which then talks to `AF_VSOCK` sockets on the host on
+//! our behalf. We only need to negotiate features, set up the vrings and let
+//! the kernel run; no per-packet userspace handling takes place on this path.
+
+use std::fs::{File, OpenOptions};
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+
+use vhost::vhost_kern::vsock::Vsock as VhostKernVsock;
+use vhost::vhost_kern::{VhostKernBackend, VhostKernFeatures};
+use vhost::vsock::VhostVsock as VhostVsockBackendTrait;
+use vhost::{Error as VhostError, VhostBackend, VringConfigData};
+use vm_memory::{GuestAddressSpace, GuestMemoryRegion};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::backend::VsockBackend;
+use super::metrics::VsockDeviceMetrics;
+use super::muxer::{Error, PassthroughQueueInfo, Result as MuxerResult, VsockGenericMuxer};
+
+/// Default path of the vhost-vsock character device exposed by the host
+/// kernel. A custom path is mostly useful for tests.
+pub const VHOST_VSOCK_DEFAULT_PATH: &str = "/dev/vhost-vsock";
+
+/// A [`VsockGenericMuxer`] implementation that delegates the entire vsock
+/// dataplane to the host kernel via `/dev/vhost-vsock`.
+///
+/// Unlike [`VsockMuxer`](super::muxer::VsockMuxer), this muxer does not keep
+/// any userspace connection state: once the vrings are handed off to the
+/// kernel during [`activate`](crate::VirtioDevice::activate), all RX/TX
+/// traffic bypasses the device model entirely. `add_backend` is therefore
+/// unsupported here - connect-to-host Unix sockets are a userspace-only
+/// concept exposed by [`VsockMuxer`](super::muxer::VsockMuxer).
+pub struct VhostVsockMuxer<AS: GuestAddressSpace> {
+    cid: u64,
+    mem: AS,
+    handle: VhostKernVsock<AS>,
+    started: bool,
+}
+
+impl<AS: GuestAddressSpace + Clone> VhostVsockMuxer<AS> {
+    /// Open `/dev/vhost-vsock` and wrap it in a muxer for the given CID.
+    pub fn new(cid: u64, mem: AS) -> MuxerResult<Self> {
+        Self::new_with_path(cid, mem, VHOST_VSOCK_DEFAULT_PATH)
+    }
+
+    /// Same as [`new`](Self::new), but opening a custom vhost-vsock device
+    /// node (e.g. a test double backed by a socketpair).
+    pub fn new_with_path(cid: u64, mem: AS, path: &str) -> MuxerResult<Self> {
+        let fd: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(Error::VhostVsockOpen)?;
+        let handle = VhostKernVsock::new(fd.as_raw_fd(), mem.clone());
+        // The fd is now owned by `handle`; avoid closing it on drop here.
+        std::mem::forget(fd);
+
+        handle.set_owner().map_err(Error::VhostVsockSetup)?;
+        handle
+            .set_guest_cid(cid)
+            .map_err(Error::VhostVsockSetup)?;
+
+        Ok(VhostVsockMuxer {
+            cid,
+            mem,
+            handle,
+            started: false,
+        })
+    }
+
+    /// Negotiate features, register the guest memory table, hand each
+    /// queue's layout and kick/call eventfds over to the kernel via
+    /// `VHOST_SET_VRING_*`, then flip the device into the running state with
+    /// `VHOST_VSOCK_SET_RUNNING`.
+    ///
+    /// `queues` describes each of the RX/TX vrings (in that order); the
+    /// event queue is not used by vhost-vsock and is left to be serviced in
+    /// userspace, exactly like crosvm does.
+    pub fn start(&mut self, queues: &[PassthroughQueueInfo]) -> MuxerResult<()> {
+        let avail_features = self
+            .handle
+            .get_features()
+            .map_err(Error::VhostVsockSetup)?;
+        self.handle
+            .set_features(avail_features)
+            .map_err(Error::VhostVsockSetup)?;
+
+        // The kernel needs the guest-physical -> host-virtual mapping before
+        // it can dereference desc_table_addr/avail_ring_addr/used_ring_addr
+        // below; without this, VHOST_VSOCK_SET_RUNNING either fails or the
+        // kernel faults on the first real packet.
+        let mem = self.mem.memory();
+        let mem_regions: Vec<vhost::VhostUserMemoryRegionInfo> = mem
+            .deref()
+            .iter()
+            .map(|region| vhost::VhostUserMemoryRegionInfo {
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len() as u64,
+                userspace_addr: region
+                    .get_host_address(vm_memory::MemoryRegionAddress(0))
+                    .map(|ptr| ptr as u64)
+                    .unwrap_or(0),
+                mmap_offset: region.file_offset().map(|fo| fo.start()).unwrap_or(0),
+                mmap_handle: region
+                    .file_offset()
+                    .map(|fo| fo.file().as_raw_fd())
+                    .unwrap_or(-1),
+            })
+            .collect();
+        self.handle
+            .set_mem_table(&mem_regions)
+            .map_err(Error::VhostVsockSetup)?;
+
+        for (idx, queue) in queues.iter().enumerate() {
+            let config = VringConfigData {
+                queue_max_size: queue.queue_size,
+                queue_size: queue.queue_size,
+                flags: 0,
+                desc_table_addr: queue.desc_table_addr,
+                used_ring_addr: queue.used_ring_addr,
+                avail_ring_addr: queue.avail_ring_addr,
+                log_addr: None,
+            };
+            self.handle
+                .set_vring_num(idx, config.queue_size)
+                .map_err(Error::VhostVsockSetup)?;
+            self.handle
+                .set_vring_addr(idx, &config)
+                .map_err(Error::VhostVsockSetup)?;
+            self.handle
+                .set_vring_base(idx, queue.avail_idx)
+                .map_err(Error::VhostVsockSetup)?;
+
+            // Safety: `kick_fd`/`call_fd` are owned by the activated queue
+            // for as long as this device stays activated, which outlives
+            // the borrows the vhost calls below take; `mem::forget` below
+            // stops the temporary `EventFd` wrapper from closing them.
+            let kick_fd = unsafe { EventFd::from_raw_fd(queue.kick_fd) };
+            self.handle
+                .set_vring_kick(idx, &kick_fd)
+                .map_err(Error::VhostVsockSetup)?;
+            std::mem::forget(kick_fd);
+
+            let call_fd = unsafe { EventFd::from_raw_fd(queue.call_fd) };
+            self.handle
+                .set_vring_call(idx, &call_fd)
+                .map_err(Error::VhostVsockSetup)?;
+            std::mem::forget(call_fd);
+        }
+
+        self.handle.start().map_err(Error::VhostVsockSetup)?;
+        self.started = true;
+        Ok(())
+    }
+
+    /// Raw fd backing `/dev/vhost-vsock`, kept around so the VMM can
+    /// register it for resource accounting if needed.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.handle.as_raw_fd()
+    }
+}
+
+impl<AS: GuestAddressSpace + Clone + Send> VsockGenericMuxer for VhostVsockMuxer<AS> {
+    fn add_backend(&mut self, _backend: Box<dyn VsockBackend>, _is_default: bool) -> MuxerResult<()> {
+        // The kernel owns the dataplane on this path: there is no userspace
+        // connection table to plug a backend into.
+        Err(Error::UnsupportedOnKernelBackend)
+    }
+
+    fn cid(&self) -> u64 {
+        self.cid
+    }
+
+    fn is_passthrough(&self) -> bool {
+        true
+    }
+
+    fn start_passthrough(
+        &mut self,
+        queues: &[PassthroughQueueInfo],
+        _mem_regions: &[vhost::VhostUserMemoryRegionInfo],
+    ) -> MuxerResult<()> {
+        self.start(queues)
+    }
+}
+
+impl From<VhostError> for Error {
+    fn from(err: VhostError) -> Self {
+        Error::VhostVsockSetup(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    use super::*;
+
+    // `/dev/vhost-vsock` is only present on hosts with the kernel module
+    // loaded, which isn't guaranteed in every test environment - skip
+    // instead of failing when it's absent.
+    #[test]
+    fn test_new_requires_vhost_vsock_device() {
+        if !Path::new(VHOST_VSOCK_DEFAULT_PATH).exists() {
+            return;
+        }
+        let mem: Arc<GuestMemoryMmap<()>> =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap());
+        let muxer = VhostVsockMuxer::new(3, mem).unwrap();
+        assert!(muxer.is_passthrough());
+        assert_eq!(muxer.cid(), 3);
+    }
+
+    struct NullBackend;
+
+    impl std::os::unix::io::AsRawFd for NullBackend {
+        fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+            -1
+        }
+    }
+
+    impl VsockBackend for NullBackend {
+        fn notify(&mut self, _evset: dbs_utils::epoll_manager::EventSet) {}
+    }
+
+    #[test]
+    fn test_add_backend_unsupported() {
+        if !Path::new(VHOST_VSOCK_DEFAULT_PATH).exists() {
+            return;
+        }
+        let mem: Arc<GuestMemoryMmap<()>> =
+            Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap());
+        let mut muxer = VhostVsockMuxer::new(3, mem).unwrap();
+        let err = muxer.add_backend(Box::new(NullBackend), false).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedOnKernelBackend));
+    }
+}
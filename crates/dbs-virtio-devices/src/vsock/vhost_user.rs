@@ -0,0 +1,234 @@
+// Copyright 2022 Alibaba Cloud. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A vhost-user based vsock muxer, letting an external process own the
+//! vsock dataplane (and, optionally, routing policy) over a Unix socket,
+//! the same way cloud-hypervisor's vhost-user block/net devices hand their
+//! queues off to an out-of-process backend daemon.
+//!
+//! Like [`VhostVsockMuxer`](super::vhost::VhostVsockMuxer), once the vrings
+//! are negotiated and handed over there is no userspace connection table
+//! left for [`add_backend`](VsockGenericMuxer::add_backend) to plug into -
+//! the backend daemon owns vsock routing policy entirely. The CID is sent to
+//! it with `VHOST_USER_SET_CONFIG`; guest reads of the device's config space
+//! are served by the device itself and are not forwarded to the daemon.
+
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use vhost::vhost_user::{Master, VhostUserMaster};
+use vhost::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::backend::VsockBackend;
+use super::defs::uapi::VSOCK_NUM_PASSTHROUGH_QUEUES;
+use super::muxer::{Error, PassthroughQueueInfo, Result as MuxerResult, VsockGenericMuxer};
+
+/// Protocol features negotiated with a vhost-user vsock backend when it
+/// offers them: `CONFIG` so the CID can be sent to it, and `REPLY_ACK` so
+/// setup requests can be confirmed. A backend that offers neither is not
+/// rejected; those features are then simply left off.
+const REQUIRED_PROTOCOL_FEATURES: u64 =
+    vhost::vhost_user::VhostUserProtocolFeatures::CONFIG.bits()
+        | vhost::vhost_user::VhostUserProtocolFeatures::REPLY_ACK.bits();
+
+/// A [`VsockGenericMuxer`] implementation that forwards the vsock dataplane
+/// to an external process over a vhost-user Unix socket, instead of running
+/// it in this process (either in userspace like [`VsockMuxer`](super::muxer::VsockMuxer),
+/// or via the host kernel like [`VhostVsockMuxer`](super::vhost::VhostVsockMuxer)).
+pub struct VhostUserVsockMuxer {
+    cid: u64,
+    socket_path: PathBuf,
+    master: Master,
+    ready: bool,
+}
+
+impl VhostUserVsockMuxer {
+    /// Connect to a vhost-user backend listening on `socket_path` and
+    /// negotiate protocol features. The CID is only written to the backend
+    /// once [`start`](Self::start) hands over the vrings - until then the
+    /// backend is free to reject it.
+    pub fn new<P: AsRef<Path>>(cid: u64, socket_path: P) -> MuxerResult<Self> {
+        let mut master = Master::connect(&socket_path, VSOCK_NUM_PASSTHROUGH_QUEUES as u64)
+            .map_err(Error::VhostUserConnect)?;
+
+        master.set_owner().map_err(Error::VhostUserSetup)?;
+        let avail_features = master.get_features().map_err(Error::VhostUserSetup)?;
+        master
+            .set_features(avail_features)
+            .map_err(Error::VhostUserSetup)?;
+
+        if avail_features & (1 << vhost::vhost_user::message::VHOST_USER_F_PROTOCOL_FEATURES) != 0
+        {
+            let avail_protocol_features = master
+                .get_protocol_features()
+                .map_err(Error::VhostUserSetup)?;
+            let protocol_features = avail_protocol_features & REQUIRED_PROTOCOL_FEATURES.into();
+            master
+                .set_protocol_features(protocol_features)
+                .map_err(Error::VhostUserSetup)?;
+        }
+
+        Ok(VhostUserVsockMuxer {
+            cid,
+            socket_path: socket_path.as_ref().to_path_buf(),
+            master,
+            ready: false,
+        })
+    }
+
+    /// Relay `VHOST_USER_SET_CONFIG` so the backend daemon learns the guest
+    /// CID, then set up the memory table and hand each vring's
+    /// kick/call eventfds over via `VHOST_USER_SET_VRING_*`.
+    pub fn start(
+        &mut self,
+        queues: &[PassthroughQueueInfo],
+        mem_regions: &[VhostUserMemoryRegionInfo],
+    ) -> MuxerResult<()> {
+        let mut cid_config = [0u8; 8];
+        cid_config.copy_from_slice(&self.cid.to_le_bytes());
+        self.master
+            .set_config(0, vhost::vhost_user::VhostUserConfigFlags::WRITABLE, &cid_config)
+            .map_err(Error::VhostUserSetup)?;
+
+        self.master
+            .set_mem_table(mem_regions)
+            .map_err(Error::VhostUserSetup)?;
+
+        for (idx, queue) in queues.iter().enumerate() {
+            let config = VringConfigData {
+                queue_max_size: queue.queue_size,
+                queue_size: queue.queue_size,
+                flags: 0,
+                desc_table_addr: queue.desc_table_addr,
+                used_ring_addr: queue.used_ring_addr,
+                avail_ring_addr: queue.avail_ring_addr,
+                log_addr: None,
+            };
+            self.master
+                .set_vring_num(idx, config.queue_size)
+                .map_err(Error::VhostUserSetup)?;
+            self.master
+                .set_vring_addr(idx, &config)
+                .map_err(Error::VhostUserSetup)?;
+            self.master
+                .set_vring_base(idx, queue.avail_idx)
+                .map_err(Error::VhostUserSetup)?;
+
+            // Safety: see the equivalent comment in `vhost.rs::start` -
+            // `kick_fd`/`call_fd` are owned by the activated queue for as
+            // long as the device stays activated, and `mem::forget` below
+            // stops this temporary `EventFd` wrapper from closing them.
+            let call_fd = unsafe { EventFd::from_raw_fd(queue.call_fd) };
+            self.master
+                .set_vring_call(idx, &call_fd)
+                .map_err(Error::VhostUserSetup)?;
+            std::mem::forget(call_fd);
+
+            let kick_fd = unsafe { EventFd::from_raw_fd(queue.kick_fd) };
+            self.master
+                .set_vring_kick(idx, &kick_fd)
+                .map_err(Error::VhostUserSetup)?;
+            std::mem::forget(kick_fd);
+
+            self.master
+                .set_vring_enable(idx, true)
+                .map_err(Error::VhostUserSetup)?;
+        }
+
+        self.ready = true;
+        Ok(())
+    }
+
+    /// Re-establish the connection to the backend daemon after it restarts,
+    /// re-running feature negotiation. The caller is expected to notice the
+    /// dropped connection (e.g. via [`connection_fd`](VsockGenericMuxer::connection_fd)
+    /// firing in the `PassthroughEpollHandler`) and re-issue `start` once
+    /// this returns.
+    ///
+    /// Any in-flight guest connections are the backend's responsibility to
+    /// preserve (or not) across its own restart - dragonball only owns the
+    /// frontend side of the socket.
+    pub fn reconnect(&mut self) -> MuxerResult<()> {
+        *self = Self::new(self.cid, self.socket_path.clone())?;
+        Ok(())
+    }
+
+    /// Whether the vrings have been successfully handed over to the
+    /// backend.
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+}
+
+impl VsockGenericMuxer for VhostUserVsockMuxer {
+    fn add_backend(&mut self, _backend: Box<dyn VsockBackend>, _is_default: bool) -> MuxerResult<()> {
+        // Routing policy lives entirely in the external backend daemon once
+        // connected; there is no in-process connection table to extend.
+        Err(Error::UnsupportedOnVhostUserBackend)
+    }
+
+    fn cid(&self) -> u64 {
+        self.cid
+    }
+
+    fn is_passthrough(&self) -> bool {
+        true
+    }
+
+    fn start_passthrough(
+        &mut self,
+        queues: &[PassthroughQueueInfo],
+        mem_regions: &[VhostUserMemoryRegionInfo],
+    ) -> MuxerResult<()> {
+        self.start(queues, mem_regions)
+    }
+
+    fn connection_fd(&self) -> Option<RawFd> {
+        Some(self.master.as_raw_fd())
+    }
+
+    fn reconnect(&mut self) -> bool {
+        // `Self::reconnect` resolves to the inherent method above (inherent
+        // methods shadow trait methods of the same name), which is what we
+        // want: it re-runs the real connect-and-negotiate sequence.
+        Self::reconnect(self).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+
+    #[test]
+    fn test_new_fails_on_missing_socket() {
+        let err = VhostUserVsockMuxer::new(3, "/nonexistent/path/to.sock").unwrap_err();
+        assert!(matches!(err, Error::VhostUserConnect(_)));
+    }
+
+    // A listener that accepts the connection but never speaks the
+    // vhost-user protocol back: `new()` should surface this as a setup
+    // error (rather than hang or panic) once it tries the first
+    // `GET_FEATURES` round-trip.
+    #[test]
+    fn test_new_fails_on_silent_backend() {
+        let dir = std::env::temp_dir().join(format!(
+            "dbs-vsock-vhost-user-test-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&dir);
+        let listener = UnixListener::bind(&dir).unwrap();
+        let accept_thread = std::thread::spawn(move || {
+            let _ = listener.accept();
+        });
+
+        let result = VhostUserVsockMuxer::new(3, &dir);
+        assert!(result.is_err());
+
+        let _ = std::fs::remove_file(&dir);
+        let _ = accept_thread.join();
+    }
+}